@@ -7,21 +7,313 @@ use std::{
     cell::RefCell,
     collections::{BTreeSet, HashSet},
 };
+use syn::parse::{Parse, ParseStream};
+use syn::Token;
 
 // CAUTION: This is all wildly unstable and relies on the compiler maintaining
 // a certain order between proc macro expansions. So this could break any
 // minute. Better have a robust CI.
 thread_local! {
-    static QUERIES: RefCell<Vec<String>> = Default::default();
+    static QUERIES: RefCell<Vec<(String, String)>> = Default::default();
 }
 
-pub(crate) fn mark_query(_args: TokenStream, input: TokenStream) -> TokenStream {
+/// The `desc { |cx| "...", args... }` modifier of a `#[query]` attribute.
+///
+/// `binder` is the name the query's context is bound to within `fmt` and
+/// `fmt_args`, mirroring how rustc's query descriptions bind `tcx`.
+struct QueryDesc {
+    binder: syn::Ident,
+    fmt: syn::LitStr,
+    fmt_args: Vec<syn::Expr>,
+}
+
+impl Parse for QueryDesc {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        input.parse::<Token![|]>()?;
+        let binder = input.parse()?;
+        input.parse::<Token![|]>()?;
+        let fmt = input.parse()?;
+        let mut fmt_args = vec![];
+        while input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+            fmt_args.push(input.parse()?);
+        }
+        Ok(QueryDesc {
+            binder,
+            fmt,
+            fmt_args,
+        })
+    }
+}
+
+/// The modifiers accepted by a `#[query(...)]` attribute.
+#[derive(Default)]
+struct QueryArgs {
+    /// A human-readable description of the query, used in trace logs and
+    /// cycle diagnostics.
+    desc: Option<QueryDesc>,
+    /// Whether the query should never be cached and always be reexecuted.
+    eval_always: bool,
+    /// Whether a cycle through this query should abort instead of
+    /// recovering via `FromCycleError`.
+    fatal_cycle: bool,
+    /// Whether the query's results should be persisted to disk between
+    /// compiler runs.
+    cache: bool,
+    /// Whether the query's results are allocated into the `Context`'s arena
+    /// instead of being cloned in and out of the cache.
+    storage_arena: bool,
+}
+
+impl Parse for QueryArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = QueryArgs::default();
+        while !input.is_empty() {
+            let ident: syn::Ident = input.parse()?;
+            if ident == "desc" {
+                let content;
+                syn::braced!(content in input);
+                args.desc = Some(content.parse()?);
+            } else if ident == "eval_always" {
+                args.eval_always = true;
+            } else if ident == "fatal_cycle" {
+                args.fatal_cycle = true;
+            } else if ident == "cache" {
+                args.cache = true;
+            } else if ident == "storage" {
+                let content;
+                syn::parenthesized!(content in input);
+                let mode: syn::Ident = content.parse()?;
+                if mode == "arena" {
+                    args.storage_arena = true;
+                } else {
+                    return Err(syn::Error::new(mode.span(), "unknown storage mode"));
+                }
+            } else {
+                return Err(syn::Error::new(ident.span(), "unknown query modifier"));
+            }
+            if input.peek(Token![,]) {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Reject modifier combinations that `derive_query_db` cannot generate
+/// correct code for, rather than silently producing code that fails to
+/// compile (or worse, compiles but cannot possibly be right) further down.
+///
+/// Panics (aborting macro expansion with a message pointing at the
+/// offending query) if `args` describes such a combination.
+fn validate_query_args(name: &syn::Ident, args: &QueryArgs) {
+    // A `storage(arena)` query's cache holds `&'t Result`, so recovering
+    // from a cycle would mean conjuring a `&'t Result` out of nothing
+    // inside `FromCycleError`, which has no access to the arena. Require
+    // `fatal_cycle` for such queries instead of generating code that
+    // cannot be implemented correctly.
+    if args.storage_arena && !args.fatal_cycle {
+        panic!(
+            "query {} uses `storage(arena)` but not `fatal_cycle`: arena-backed \
+             results cannot be recovered from a cycle, so `storage(arena)` queries \
+             must also be marked `fatal_cycle`",
+            name
+        );
+    }
+}
+
+/// The parent-pointer walk the `#[query]`-generated code performs to detect
+/// a cycle in O(1) space per hop, kept here in a plain, testable form.
+///
+/// Starting at `start` (the job currently running on the requesting
+/// thread), follow `parent_of` one hop at a time, collecting each tag
+/// visited into the returned chain. If `target` is reached, the chain
+/// (`target` itself, followed by every tag from `start` up to and
+/// including `target` again) describes the cycle; otherwise `start`'s
+/// chain does not loop back onto `target` and `None` is returned.
+///
+/// The generated code inlines this exact walk (it cannot call back into
+/// this crate at run time, since a `proc-macro = true` crate has no normal
+/// runtime artifact for the generated code to link against) seeded from
+/// `query_storage.jobs.lock().get(&thread_id)`, not from `target`'s own
+/// stored parent -- seeding from the latter only follows whoever first
+/// requested `target`, whose chain never leads back to `target`, so the
+/// cycle closed by the current requester would be missed.
+#[cfg(test)]
+fn find_cycle<K: Eq + Clone>(
+    start: Option<K>,
+    target: &K,
+    parent_of: impl Fn(&K) -> Option<K>,
+) -> Option<Vec<K>> {
+    let mut chain = vec![target.clone()];
+    let mut cursor = start;
+    while let Some(tag) = cursor {
+        chain.push(tag.clone());
+        if tag == *target {
+            return Some(chain);
+        }
+        cursor = parent_of(&tag);
+    }
+    None
+}
+
+/// The same walk as [`find_cycle`], but against the actual shape of the
+/// generated `inflight` map: `tag -> Option<parent>`, where a stored parent
+/// of `None` marks `tag` as the root of its call chain (it is still
+/// in-flight, it simply has no further parent), as opposed to `tag` being
+/// altogether absent from the map. Modelling that distinction matters: an
+/// earlier version of the generated code conflated "no parent" with "parent
+/// is itself" by storing a self-edge for root queries, which made this walk
+/// loop forever on any root-level contention that wasn't actually a cycle.
+#[cfg(test)]
+fn find_cycle_in_inflight<K: Eq + Clone + std::hash::Hash>(
+    inflight: &std::collections::HashMap<K, Option<K>>,
+    start: Option<K>,
+    target: &K,
+) -> Option<Vec<K>> {
+    let mut chain = vec![target.clone()];
+    let mut cursor = start;
+    while let Some(tag) = cursor {
+        chain.push(tag.clone());
+        if tag == *target {
+            return Some(chain);
+        }
+        cursor = match inflight.get(&tag) {
+            Some(parent) => parent.clone(),
+            None => break,
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_args_parses_all_modifiers() {
+        let args: QueryArgs =
+            syn::parse_str(r#"desc { |cx| "resolving {}", name }, eval_always, fatal_cycle, cache, storage(arena)"#)
+                .unwrap();
+        assert!(args.desc.is_some());
+        assert!(args.eval_always);
+        assert!(args.fatal_cycle);
+        assert!(args.cache);
+        assert!(args.storage_arena);
+    }
+
+    #[test]
+    fn query_args_defaults_to_empty() {
+        let args: QueryArgs = syn::parse_str("").unwrap();
+        assert!(args.desc.is_none());
+        assert!(!args.eval_always);
+        assert!(!args.fatal_cycle);
+        assert!(!args.cache);
+        assert!(!args.storage_arena);
+    }
+
+    #[test]
+    fn query_args_rejects_unknown_modifier() {
+        let result: syn::Result<QueryArgs> = syn::parse_str("not_a_real_modifier");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn validate_query_args_rejects_arena_without_fatal_cycle() {
+        let name = format_ident!("some_query");
+        let mut args = QueryArgs::default();
+        args.storage_arena = true;
+        let result = std::panic::catch_unwind(|| validate_query_args(&name, &args));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn validate_query_args_allows_arena_with_fatal_cycle() {
+        let name = format_ident!("some_query");
+        let mut args = QueryArgs::default();
+        args.storage_arena = true;
+        args.fatal_cycle = true;
+        validate_query_args(&name, &args);
+    }
+
+    /// `R` calls `A`, `A` calls `B`, `B` calls `A` again -- the textbook
+    /// mutual-recursion case this feature exists for. Seeded from the
+    /// requester (`B`), the walk must find `A` and report the cycle,
+    /// rather than seeding from `A`'s own stored parent (`R`) and missing
+    /// the cycle since `R`'s chain never reaches `A`.
+    #[test]
+    fn find_cycle_detects_mutual_recursion() {
+        let parent_of = |tag: &&str| match *tag {
+            "B" => Some("A"),
+            "A" => Some("R"),
+            _ => None,
+        };
+        let cycle = find_cycle(Some("B"), &"A", parent_of);
+        assert_eq!(cycle, Some(vec!["A", "B", "A"]));
+    }
+
+    #[test]
+    fn find_cycle_detects_direct_self_request() {
+        let cycle = find_cycle(Some("A"), &"A", |_: &&str| None);
+        assert_eq!(cycle, Some(vec!["A", "A"]));
+    }
+
+    #[test]
+    fn find_cycle_terminates_when_there_is_none() {
+        let parent_of = |tag: &&str| match *tag {
+            "B" => Some("R"),
+            _ => None,
+        };
+        let cycle = find_cycle(Some("B"), &"A", parent_of);
+        assert_eq!(cycle, None);
+    }
+
+    /// `R` is an in-flight root query (still running, hence still in the
+    /// map, but with a stored parent of `None`) with `A` nested under it.
+    /// A thread whose current job is `A` requests a query `X` that another
+    /// thread already owns -- legitimate cross-thread contention on a
+    /// shared subquery, not a cycle. The walk must ascend from `A` to `R`
+    /// and then stop there, rather than spinning forever on a fabricated
+    /// `R -> R` self-edge (the bug this representation exists to rule out).
+    #[test]
+    fn find_cycle_in_inflight_terminates_at_root_without_self_parent() {
+        let mut inflight = std::collections::HashMap::new();
+        inflight.insert("R", None);
+        inflight.insert("A", Some("R"));
+        let cycle = find_cycle_in_inflight(&inflight, Some("A"), &"X");
+        assert_eq!(cycle, None);
+    }
+
+    #[test]
+    fn find_cycle_in_inflight_detects_mutual_recursion() {
+        let mut inflight = std::collections::HashMap::new();
+        inflight.insert("R", None);
+        inflight.insert("A", Some("R"));
+        inflight.insert("B", Some("A"));
+        let cycle = find_cycle_in_inflight(&inflight, Some("B"), &"A");
+        assert_eq!(cycle, Some(vec!["A", "B", "A"]));
+    }
+
+    #[test]
+    fn find_cycle_in_inflight_detects_direct_self_request() {
+        let mut inflight = std::collections::HashMap::new();
+        inflight.insert("A", None);
+        let cycle = find_cycle_in_inflight(&inflight, Some("A"), &"A");
+        assert_eq!(cycle, Some(vec!["A", "A"]));
+    }
+}
+
+pub(crate) fn mark_query(args: TokenStream, input: TokenStream) -> TokenStream {
     // Parse the input.
     let input = syn::parse_macro_input!(input as syn::ItemFn);
 
     // Map everything to a string here. Compiler panics horribly if we hand out
-    // the actual idents and generics.
-    QUERIES.with(|c| c.borrow_mut().push(input.to_token_stream().to_string()));
+    // the actual idents and generics. The modifier args are stashed alongside
+    // so `derive_query_db` can parse them once it knows the query's name.
+    QUERIES.with(|c| {
+        c.borrow_mut()
+            .push((args.to_string(), input.to_token_stream().to_string()))
+    });
 
     // Produce some output.
     let output = quote! { #input };
@@ -46,12 +338,22 @@ pub(crate) fn derive_query_db(input: TokenStream) -> TokenStream {
 
     // Process the queries.
     let mut funcs = vec![];
+    let mut describes = vec![];
     let mut caches = vec![];
     let mut query_tags = vec![];
+    let mut invalidate_arms = vec![];
+    let mut encode_arms = vec![];
+    let mut decode_arms = vec![];
+    let mut serde_asserts = vec![];
 
-    for raw_query in &queries {
-        // Parse the fn.
+    for (raw_args, raw_query) in &queries {
+        // Parse the fn and its modifiers.
         let query: syn::ItemFn = syn::parse_str(raw_query).unwrap();
+        let query_args: QueryArgs = if raw_args.trim().is_empty() {
+            Default::default()
+        } else {
+            syn::parse_str(raw_args).unwrap()
+        };
 
         // Disect a few things.
         let name = query.sig.ident.clone();
@@ -62,6 +364,8 @@ pub(crate) fn derive_query_db(input: TokenStream) -> TokenStream {
             _ => panic!("query {} has no return type", name),
         };
 
+        validate_query_args(&name, &query_args);
+
         // Filter out the doc comments such that we can apply them to the trait
         // fn as well.
         let doc_attrs = query.attrs.iter().filter(|a| a.path.is_ident("doc"));
@@ -107,6 +411,28 @@ pub(crate) fn derive_query_db(input: TokenStream) -> TokenStream {
         // Determine the cache field name.
         let cache_name = format_ident!("cached_{}", name);
 
+        // Queries marked `storage(arena)` allocate their result into the
+        // `Context`'s arena and only ever hand out `&'t Result`, so both
+        // insertion and cache hits are a cheap reference copy rather than a
+        // clone of the whole value.
+        let arena_lt = syn::Lifetime::new("'t", proc_macro2::Span::call_site());
+        let result_ty = if query_args.storage_arena {
+            quote! { &#arena_lt #result }
+        } else {
+            quote! { #result }
+        };
+        let arena_convert = if query_args.storage_arena {
+            quote! {
+                let result: #result_ty = AllocInto::alloc(self.context(), result);
+            }
+        } else {
+            quote! {}
+        };
+
+        // Determine the describe fn name, used to render a human-readable
+        // description of the query in trace logs and cycle diagnostics.
+        let describe_name = format_ident!("describe_{}", name);
+
         // Render a query tag that can be pushed onto the query stack to break
         // cycles.
         let tag_name = format_ident!("{}", name.to_string().to_camel_case());
@@ -116,46 +442,263 @@ pub(crate) fn derive_query_db(input: TokenStream) -> TokenStream {
             #tag_name (#key_type),
         });
 
+        // Render the describe fn, either from the `desc { .. }` modifier or
+        // as a generic fallback based on the query key.
+        let describe_body = match &query_args.desc {
+            Some(desc) => {
+                let binder = &desc.binder;
+                let fmt = &desc.fmt;
+                let fmt_args = &desc.fmt_args;
+                quote! {
+                    let #binder = self.context();
+                    format!(#fmt, #(#fmt_args),*)
+                }
+            }
+            None => quote! {
+                format!("{} {:?}", stringify!(#name), #key)
+            },
+        };
+        let describe_doc = format!("Describe an invocation of the `{}` query.", name);
+        describes.push(quote! {
+            #[doc = #describe_doc]
+            fn #describe_name #generics (&self, #(#arg_names: #arg_types),*) -> String {
+                #describe_body
+            }
+        });
+
+        // Queries marked `eval_always` are never looked up in or written to
+        // the cache, so they are always reexecuted.
+        let cache_lookup = if query_args.eval_always {
+            quote! {}
+        } else {
+            quote! {
+                if let Some(result) = query_storage.#cache_name.read().get(&query_key) {
+                    trace!("Serving {} from cache", self.#describe_name(#(#arg_names),*));
+                    return result.clone();
+                }
+            }
+        };
+        let cache_insert = if query_args.eval_always {
+            quote! {}
+        } else {
+            quote! {
+                query_storage.#cache_name.write().insert(query_key, result.clone());
+            }
+        };
+        // After waiting on someone else's latch, non-cached queries have
+        // nowhere to read the result back from, so they simply fall through
+        // and recompute their own copy instead of sharing one.
+        let post_wait = if query_args.eval_always {
+            quote! {}
+        } else {
+            quote! {
+                return query_storage
+                    .#cache_name
+                    .read()
+                    .get(&query_key)
+                    .expect("result missing after waiting on query latch")
+                    .clone();
+            }
+        };
+
+        // Queries marked `fatal_cycle` abort immediately on a cycle. All
+        // other queries recover by synthesizing a sentinel result via
+        // `FromCycleError`, using the parent-pointer chain from the query
+        // that closed the cycle back up to itself, already collected into
+        // `cycle_stack` by the caller.
+        let cycle_handler = if query_args.fatal_cycle {
+            quote! { self.handle_fatal_cycle(); }
+        } else {
+            let cycle_cache_insert = if query_args.eval_always {
+                quote! {}
+            } else {
+                quote! {
+                    query_storage.#cache_name.write().insert(query_key.clone(), result.clone());
+                }
+            };
+            quote! {
+                // `lts` isn't fully known until every query has been
+                // scanned, so the trait's lifetime params are left for the
+                // compiler to infer here rather than spelled out.
+                let result: #result_ty =
+                    <#result_ty as FromCycleError>::from_cycle_error(self, &cycle_stack);
+                #cycle_cache_insert
+                return result;
+            }
+        };
+
         // Render the query for the database trait.
         funcs.push(quote! {
             #(#doc_attrs)*
-            fn #name #generics (&self, #(#arg_names: #arg_types),*) -> #result {
+            fn #name #generics (&self, #(#arg_names: #arg_types),*) -> #result_ty {
                 let query_storage = self.storage();
                 let query_key = #key;
                 let query_tag = QueryTag::#tag_name(query_key.clone());
+                let thread_id = std::thread::current().id();
+
+                // Record that whoever is currently executing on this thread
+                // depends on the result of this query.
+                if let Some(caller) = query_storage.jobs.lock().get(&thread_id).cloned() {
+                    query_storage
+                        .deps
+                        .lock()
+                        .entry(caller)
+                        .or_insert_with(HashSet::new)
+                        .insert(query_tag.clone());
+                }
 
                 // Check if we already have a result for this query.
-                if let Some(result) = query_storage.#cache_name.borrow().get(&query_key) {
-                    trace!("Serving {} {:?} from cache", stringify!(#name), query_key);
-                    return result.clone();
+                #cache_lookup
+
+                // Claim this query for computation on this thread, or find
+                // the latch of whoever already owns it. Membership and the
+                // owner's job are both O(1) lookups in the in-flight map.
+                let owns_slot;
+                let existing_latch = {
+                    let mut inflight = query_storage.inflight.lock();
+                    match inflight.get(&query_tag).map(|(_, latch)| latch.clone()) {
+                        Some(latch) => {
+                            // Someone else already owns this query. Walk the
+                            // parent-pointer chain starting at whoever is
+                            // making *this* request (not at `query_tag`'s own
+                            // stored parent, which only reflects who
+                            // originally requested it), one O(1) hop at a
+                            // time, to see whether we are, transitively,
+                            // waiting on our own result. `None` means the
+                            // walk ran off the top of a call chain (a root
+                            // query with no further parent) -- not a cycle,
+                            // so the loop must stop there rather than
+                            // looping on a fabricated self-edge.
+                            let mut cycle_stack = vec![query_tag.clone()];
+                            let mut cursor = query_storage.jobs.lock().get(&thread_id).cloned();
+                            let mut is_cycle = false;
+                            while let Some(tag) = cursor {
+                                cycle_stack.push(tag.clone());
+                                if tag == query_tag {
+                                    is_cycle = true;
+                                    break;
+                                }
+                                cursor = match inflight.get(&tag) {
+                                    Some((parent, _)) => parent.clone(),
+                                    None => break,
+                                };
+                            }
+                            if is_cycle {
+                                #cycle_handler
+                            }
+                            owns_slot = false;
+                            Some(latch)
+                        }
+                        None => {
+                            // `None` if this thread has no current job, i.e.
+                            // this query is the root of its call chain.
+                            let parent = query_storage.jobs.lock().get(&thread_id).cloned();
+                            inflight.insert(
+                                query_tag.clone(),
+                                (parent, std::sync::Arc::new(QueryLatch::new())),
+                            );
+                            owns_slot = true;
+                            None
+                        }
+                    }
+                };
+                if let Some(latch) = existing_latch {
+                    // Block until the owning thread publishes a result,
+                    // rather than recomputing it ourselves.
+                    latch.wait();
+                    #post_wait
                 }
-                trace!("Executing {} {:?}", stringify!(#name), query_key);
 
-                // Push the query onto the stack, checking if it is already in
-                // flight.
-                query_storage.stack.borrow_mut().push(query_tag.clone());
-                if !query_storage.inflight.borrow_mut().insert(query_tag.clone()) {
-                    self.handle_cycle();
-                    // The above never returns.
-                }
+                trace!("Executing {}", self.#describe_name(#(#arg_names),*));
 
-                // Execute the query.
+                // Execute the query, marking it as the currently-executing
+                // one on this thread so that any query it calls records a
+                // dependency edge, and a parent pointer, back to it.
+                let previous_job = query_storage.jobs.lock().insert(thread_id, query_tag.clone());
                 let result: #result = #name(self.context(), #(#arg_names),*);
-                query_storage.#cache_name.borrow_mut().insert(query_key, result.clone());
+                #arena_convert
+                match previous_job {
+                    Some(job) => {
+                        query_storage.jobs.lock().insert(thread_id, job);
+                    }
+                    None => {
+                        query_storage.jobs.lock().remove(&thread_id);
+                    }
+                }
+                #cache_insert
 
-                // Pop the query from the stack.
-                query_storage.inflight.borrow_mut().remove(&query_tag);
-                query_storage.stack.borrow_mut().pop();
+                // Release whoever is blocked waiting on our result.
+                if owns_slot {
+                    if let Some((_, latch)) = query_storage.inflight.lock().remove(&query_tag) {
+                        latch.signal();
+                    }
+                }
                 result
             }
         });
 
-        // Render the cache field for the storage struct.
-        let doc = format!("Cached results of the `{}` query.", name);
-        caches.push(quote! {
-            #[doc = #doc]
-            pub #cache_name: RefCell<HashMap<#key_type, #result>>,
-        });
+        // Render the cache field for the storage struct, unless the query
+        // opted out of caching via `eval_always`.
+        if !query_args.eval_always {
+            let doc = format!("Cached results of the `{}` query.", name);
+            caches.push(quote! {
+                #[doc = #doc]
+                pub #cache_name: parking_lot::RwLock<HashMap<#key_type, #result_ty>>,
+            });
+            invalidate_arms.push(quote! {
+                QueryTag::#tag_name(key) => {
+                    self.#cache_name.write().remove(key);
+                }
+            });
+        } else {
+            invalidate_arms.push(quote! {
+                QueryTag::#tag_name(_) => {}
+            });
+        }
+
+        // Render the on-disk persistence hooks for queries marked `cache`.
+        // Entries are tagged by the query's name so a single cache file can
+        // hold the results of every persisted query disambiguated by it.
+        // Arena-backed queries hold a `&'t Result` rather than an owned
+        // value, so they have nothing serializable to persist.
+        if query_args.cache && !query_args.eval_always && !query_args.storage_arena {
+            let tag_str = name.to_string();
+
+            // Gate persistence on the key and result actually implementing
+            // `serde::Serialize`/`Deserialize`, so a non-serializable query
+            // marked `cache` fails here, at the modifier site, rather than
+            // inside the `bincode::serialize`/`deserialize` calls below with
+            // a confusing, indirect trait-bound error.
+            let assert_name = format_ident!("__assert_{}_cache_is_serializable", name);
+            serde_asserts.push(quote! {
+                #[allow(non_snake_case, dead_code)]
+                fn #assert_name #generics()
+                where
+                    #key_type: serde::Serialize + serde::de::DeserializeOwned,
+                    #result: serde::Serialize + serde::de::DeserializeOwned,
+                {
+                }
+            });
+
+            encode_arms.push(quote! {
+                for (key, result) in self.#cache_name.read().iter() {
+                    entries.push((
+                        #tag_str,
+                        bincode::serialize(&(key, result))
+                            .expect("failed to encode query cache entry"),
+                    ));
+                }
+            });
+            decode_arms.push(quote! {
+                #tag_str => {
+                    if let Ok((key, result)) =
+                        bincode::deserialize::<(#key_type, #result)>(&bytes)
+                    {
+                        self.#cache_name.write().insert(key, result);
+                    }
+                }
+            });
+        }
     }
 
     // Extract query lifetimes.
@@ -181,29 +724,141 @@ pub(crate) fn derive_query_db(input: TokenStream) -> TokenStream {
             /// Get the query caches and runtime data.
             fn storage(&self) -> &QueryStorage #lts;
 
-            /// Called when a query cycle is detected.
-            fn handle_cycle(&self) -> ! {
-                panic!("query cycle detected");
+            /// Called when a query marked `fatal_cycle` hits a cycle.
+            ///
+            /// Unlike the `FromCycleError` recovery path other queries use,
+            /// this is never meant to recover.
+            fn handle_fatal_cycle(&self) -> ! {
+                panic!("fatal query cycle detected");
             }
 
             #(#funcs)*
+            #(#describes)*
         }
     });
 
     // Generate the query storage struct.
     output.extend(quote! {
-        /// A collection of query caches and runtime data for a `QueryDatabase`.
+        /// A collection of query caches and runtime data for a `QueryDatabase`,
+        /// safe to share between threads so queries can run in parallel.
         #[derive(Default)]
         pub struct QueryStorage #lts {
-            /// A stack of the currently-executing queries.
-            pub stack: RefCell<Vec<QueryTag #lts>>,
-            /// A set of the currently-executing queries.
-            pub inflight: RefCell<HashSet<QueryTag #lts>>,
+            /// The queries currently being computed, keyed by tag, each
+            /// paired with the tag of the job that requested it (`None` for
+            /// a query with no current job on its thread, i.e. the root of
+            /// a call chain) and a latch other threads can block on until
+            /// the result is ready. This doubles as the in-flight set for
+            /// O(1) cycle detection: a cycle exists iff walking the parent
+            /// pointers from the requesting job leads back to the tag being
+            /// requested, rather than running out at a root.
+            pub inflight: parking_lot::Mutex<HashMap<QueryTag #lts, (Option<QueryTag #lts>, std::sync::Arc<QueryLatch>)>>,
+            /// The query each thread is currently computing, if any, used to
+            /// determine the parent of a newly-requested query and to record
+            /// dependency edges as nested queries are called.
+            pub jobs: parking_lot::Mutex<HashMap<std::thread::ThreadId, QueryTag #lts>>,
+            /// A map from each query to the set of queries it depends on.
+            pub deps: parking_lot::Mutex<HashMap<QueryTag #lts, HashSet<QueryTag #lts>>>,
 
             #(#caches)*
         }
     });
 
+    // Generate the latch that queries block on while another thread is
+    // computing their result.
+    output.extend(quote! {
+        /// A one-shot latch that lets threads block until a query result
+        /// computed elsewhere becomes available.
+        #[derive(Default)]
+        pub struct QueryLatch {
+            ready: parking_lot::Mutex<bool>,
+            cond: parking_lot::Condvar,
+        }
+
+        impl QueryLatch {
+            /// Create a new, unsignalled latch.
+            pub fn new() -> QueryLatch {
+                Default::default()
+            }
+
+            /// Block the calling thread until `signal` is called.
+            pub fn wait(&self) {
+                let mut ready = self.ready.lock();
+                while !*ready {
+                    self.cond.wait(&mut ready);
+                }
+            }
+
+            /// Wake up every thread blocked in `wait`.
+            pub fn signal(&self) {
+                let mut ready = self.ready.lock();
+                *ready = true;
+                self.cond.notify_all();
+            }
+        }
+    });
+
+    // Generate the dependency-graph invalidation logic.
+    output.extend(quote! {
+        impl #lts QueryStorage #lts {
+            /// Invalidate the cached result of `tag`, along with every query
+            /// that transitively depends on it, so that only the affected
+            /// subset is recomputed on the next call of each query rather than
+            /// discarding the whole cache.
+            pub fn invalidate(&self, tag: &QueryTag #lts) {
+                let mut worklist = vec![tag.clone()];
+                let mut seen = HashSet::new();
+                while let Some(tag) = worklist.pop() {
+                    if !seen.insert(tag.clone()) {
+                        continue;
+                    }
+                    match &tag {
+                        #(#invalidate_arms)*
+                    }
+                    for (dependent, dependencies) in self.deps.lock().iter() {
+                        if dependencies.contains(&tag) {
+                            worklist.push(dependent.clone());
+                        }
+                    }
+                }
+            }
+
+            /// Serialize the results of every `cache`-marked query to
+            /// `path`, so a later run can load them back via
+            /// `load_from_disk` instead of recomputing them.
+            pub fn save_to_disk(&self, path: &std::path::Path) -> std::io::Result<()> {
+                let mut entries: Vec<(&'static str, Vec<u8>)> = Vec::new();
+                #(#encode_arms)*
+                let bytes =
+                    bincode::serialize(&entries).expect("failed to encode query cache");
+                std::fs::write(path, bytes)
+            }
+
+            /// Load the results of every `cache`-marked query previously
+            /// persisted to `path` via `save_to_disk`, pre-populating their
+            /// caches.
+            pub fn load_from_disk(&self, path: &std::path::Path) -> std::io::Result<()> {
+                let bytes = std::fs::read(path)?;
+                let entries: Vec<(String, Vec<u8>)> =
+                    bincode::deserialize(&bytes).expect("failed to decode query cache");
+                for (tag, bytes) in entries {
+                    match tag.as_str() {
+                        #(#decode_arms)*
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+
+            // Assertions that every `cache`-marked query's key and result
+            // are actually (de)serializable. They are never called; their
+            // only purpose is to turn a missing `Serialize`/`Deserialize`
+            // impl into a clear error pointing at the query itself, rather
+            // than at the `bincode` calls above. Declared here, inside the
+            // `impl #lts`, so they pick up the arena lifetime in scope.
+            #(#serde_asserts)*
+        }
+    });
+
     // Generate the query tag enum.
     output.extend(quote! {
         /// A tag identifying any of the queries in `QueryDatabase`.
@@ -213,6 +868,26 @@ pub(crate) fn derive_query_db(input: TokenStream) -> TokenStream {
         }
     });
 
+    // Generate the cycle-recovery trait used by queries that did not opt
+    // into `fatal_cycle`.
+    output.extend(quote! {
+        /// Produces a sentinel result for a query caught in a dependency
+        /// cycle, so that elaboration can continue and report a proper
+        /// diagnostic instead of aborting.
+        pub trait FromCycleError #lts {
+            /// Construct a sentinel value for a query whose result is part
+            /// of the cycle described by `stack`, which starts with the
+            /// query that was requested again, followed by the query that
+            /// closed the cycle and its parents back up to that same query.
+            ///
+            /// `db` is passed through so an implementation can render a
+            /// proper diagnostic via the `describe_<name>` methods on
+            /// `QueryDatabase`, the same descriptions used in trace logs,
+            /// rather than being limited to the bare `QueryTag` stack.
+            fn from_cycle_error<DB: QueryDatabase #lts + ?Sized>(db: &DB, stack: &[QueryTag #lts]) -> Self;
+        }
+    });
+
     // Produce some output.
     // println!("{}", output);
     output.into()
@@ -23,7 +23,7 @@ make_arenas!(
 );
 
 /// A placeholder for an HIR node.
-pub struct Slot<'t, T>(RefCell<SlotState<'t, T>>)
+pub struct Slot<'t, T>(Span, RefCell<SlotState<'t, T>>)
 where
     T: FromAst<'t> + 't;
 
@@ -33,6 +33,9 @@ where
     T: FromAst<'t> + 't,
 {
     Fresh(&'t AnyScope, T::Input, T::Arena),
+    /// The node is currently being created from its AST. Polling a slot in
+    /// this state means the declaration it holds depends on itself.
+    InFlight,
     ReadyOk(&'t T),
     ReadyErr,
 }
@@ -43,23 +46,41 @@ where
     T::Arena: AllocInto<'t, T> + Clone,
 {
     /// Create a new slot.
-    pub fn new(scope: &'t AnyScope, ast: T::Input, arena: T::Arena) -> Slot<'t, T> {
-        Slot(RefCell::new(SlotState::Fresh(scope, ast, arena)))
+    pub fn new(scope: &'t AnyScope, ast: T::Input, arena: T::Arena, span: Span) -> Slot<'t, T> {
+        Slot(span, RefCell::new(SlotState::Fresh(scope, ast, arena)))
     }
 
     /// Poll the slot, creating the HIR node from the AST the first time.
     pub fn poll(&self) -> Result<&'t T, ()> {
-        match *self.0.borrow() {
+        let cyclic = match *self.1.borrow() {
             SlotState::ReadyOk(x) => return Ok(x),
             SlotState::ReadyErr => return Err(()),
-            _ => (),
+            SlotState::InFlight => true,
+            _ => false,
+        };
+        if cyclic {
+            // Re-entering a slot that is still being computed means the
+            // declaration it holds (transitively) depends on itself, e.g.
+            // two packages or types that mutually reference each other.
+            // Report it and resolve to an error deliberately, rather than
+            // the silent `Err(())` a plain re-entrant poll would otherwise
+            // produce with no diagnostic at all. `debugln!` alone would not
+            // do, since it is compiled out of a normal build; this has to
+            // reach the user.
+            eprintln!(
+                "error: declaration cycle: {} at {:?} depends on itself",
+                std::any::type_name::<T>(),
+                self.0
+            );
+            self.1.replace(SlotState::ReadyErr);
+            return Err(());
         }
-        let (scope, ast, arena) = match self.0.replace(SlotState::ReadyErr) {
+        let (scope, ast, arena) = match self.1.replace(SlotState::InFlight) {
             SlotState::Fresh(scope, ast, arena) => (scope, ast, arena),
             _ => unreachable!(),
         };
         let node = T::from_ast(scope, ast, arena.clone()).map(|x| arena.alloc(x) as &T);
-        self.0.replace(match node {
+        self.1.replace(match node {
             Ok(x) => SlotState::ReadyOk(x),
             Err(()) => SlotState::ReadyErr,
         });
@@ -101,7 +122,7 @@ impl<'t> FromAst<'t> for Package2<'t> {
         arena: Self::Arena,
     ) -> Result<Slot<'t, Self>, ()> {
         // TODO: register the package name in the scope
-        Ok(Slot::new(scope, ast, arena))
+        Ok(Slot::new(scope, ast, arena, ast.span))
     }
 
     fn from_ast(scope: &'t AnyScope, ast: Self::Input, arena: Self::Arena) -> Result<Self, ()> {
@@ -153,7 +174,7 @@ impl<'t> FromAst<'t> for TypeDecl2 {
         arena: Self::Arena,
     ) -> Result<Slot<'t, Self>, ()> {
         // TODO: register the type name in the scope
-        Ok(Slot::new(scope, ast, arena))
+        Ok(Slot::new(scope, ast, arena, ast.span))
     }
 
     fn from_ast(_scope: &'t AnyScope, ast: Self::Input, _arena: Self::Arena) -> Result<Self, ()> {